@@ -6,11 +6,10 @@ use std::fs::File;
 use std::io::{Error, ErrorKind, Result};
 use std::os::unix::io::AsRawFd;
 use std::convert::TryInto;
-use vm_memory::VolatileSlice;
+use libc::{c_int, c_void};
+use vm_memory::{GuestMemoryRegion, VolatileSlice};
 
-use libc::{c_int, c_void, read, readv, size_t, write, writev};
-
-use super::bindings::{off64_t, pread64, preadv64, pwrite64, pwritev64, mmap,memcpy,msync,cwrite,lseek64,pcwrite};
+use super::bindings::{off64_t, pread64, preadv64, pwrite64, pwritev64, mmap,memcpy,msync,pcwrite, fallocate64};
 
 /// A trait for setting the size of a file.
 /// This is equivalent to File's `set_len` method, but
@@ -28,6 +27,98 @@ impl FileSetLen for File {
     }
 }
 
+/// A trait for preallocating space for a file, equivalent to `fallocate(2)` with no flags.
+pub trait FileAllocate {
+    /// Allocates `len` bytes starting at `offset`, so that a later write to that range does
+    /// not fail because of insufficient disk space.
+    fn allocate(&self, offset: u64, len: u64) -> Result<()>;
+}
+
+/// A trait for reclaiming the backing blocks of a range of a file without changing its
+/// apparent size, equivalent to `fallocate(2)` with `FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE`.
+pub trait FilePunchHole {
+    /// Reclaims the disk space backing `[offset, offset + len)`, leaving that range reading back
+    /// as zeroes. Used to service guest DISCARD/TRIM requests on a disk image.
+    fn punch_hole(&self, offset: u64, len: u64) -> Result<()>;
+}
+
+/// A trait for durably persisting file contents to the backing storage.
+pub trait FileSync {
+    /// Flushes all data and metadata for this file to disk, equivalent to `fsync(2)`.
+    fn fsync(&self) -> Result<()>;
+
+    /// Flushes data for this file to disk, equivalent to `fdatasync(2)`. Unlike `fsync`, this
+    /// does not guarantee that file metadata (e.g. size) is also persisted.
+    fn fdatasync(&self) -> Result<()>;
+}
+
+/// Maps an `EOPNOTSUPP`/`ENOSYS` `fallocate` failure to `ErrorKind::Unsupported` so that callers
+/// can fall back gracefully on filesystems that reject the operation, instead of treating it as
+/// a hard I/O error.
+fn fallocate_result(ret: c_int) -> Result<()> {
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Err(Error::from(ErrorKind::Unsupported)),
+        _ => Err(err),
+    }
+}
+
+macro_rules! file_ops_impl {
+    ($ty:ty) => {
+        impl FileAllocate for $ty {
+            fn allocate(&self, offset: u64, len: u64) -> Result<()> {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let ret = unsafe {
+                    fallocate64(self.as_raw_fd(), 0, offset as off64_t, len as off64_t)
+                };
+                fallocate_result(ret)
+            }
+        }
+
+        impl FilePunchHole for $ty {
+            fn punch_hole(&self, offset: u64, len: u64) -> Result<()> {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let ret = unsafe {
+                    fallocate64(
+                        self.as_raw_fd(),
+                        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                        offset as off64_t,
+                        len as off64_t,
+                    )
+                };
+                fallocate_result(ret)
+            }
+        }
+
+        impl FileSync for $ty {
+            fn fsync(&self) -> Result<()> {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let ret = unsafe { libc::fsync(self.as_raw_fd()) };
+                if ret == 0 {
+                    Ok(())
+                } else {
+                    Err(Error::last_os_error())
+                }
+            }
+
+            fn fdatasync(&self) -> Result<()> {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let ret = unsafe { libc::fdatasync(self.as_raw_fd()) };
+                if ret == 0 {
+                    Ok(())
+                } else {
+                    Err(Error::last_os_error())
+                }
+            }
+        }
+    };
+}
+
+file_ops_impl!(File);
+
 /// A trait similar to `Read` and `Write`, but uses volatile memory as buffers.
 pub trait FileReadWriteVolatile {
     /// Read bytes from this file into the given slice, returning the number of bytes read on
@@ -128,14 +219,23 @@ pub trait FileReadWriteAtVolatile {
     /// Like `read_at_volatile`, except it reads to a slice of buffers. Data is copied to fill each
     /// buffer in order, with the final buffer written to possibly being only partially filled. This
     /// method must behave as a single call to `read_at_volatile` with the buffers concatenated
-    /// would. The default implementation calls `read_at_volatile` with either the first nonempty
-    /// buffer provided, or returns `Ok(0)` if none exists.
-    fn read_vectored_at_volatile(&mut self, bufs: &[VolatileSlice], offset: u64) -> Result<usize> {
-        if let Some(&slice) = bufs.first() {
-            self.read_at_volatile(slice, offset)
-        } else {
-            Ok(0)
+    /// would. The default implementation calls `read_at_volatile` once per buffer, advancing
+    /// `offset` by the number of bytes actually read each time, and stops at the first buffer that
+    /// is read only partially (or not at all).
+    fn read_vectored_at_volatile(&mut self, bufs: &[VolatileSlice], mut offset: u64) -> Result<usize> {
+        let mut total = 0;
+        for &slice in bufs {
+            if slice.is_empty() {
+                continue;
+            }
+            let n = self.read_at_volatile(slice, offset)?;
+            total += n;
+            offset = offset.checked_add(n as u64).unwrap();
+            if n < slice.len() {
+                break;
+            }
         }
+        Ok(total)
     }
 
     /// Reads bytes from this file at `offset` into the given slice until all bytes in the slice are
@@ -162,14 +262,23 @@ pub trait FileReadWriteAtVolatile {
     /// Like `write_at_at_volatile`, except that it writes from a slice of buffers. Data is copied
     /// from each buffer in order, with the final buffer read from possibly being only partially
     /// consumed. This method must behave as a call to `write_at_volatile` with the buffers
-    /// concatenated would. The default implementation calls `write_at_volatile` with either the
-    /// first nonempty buffer provided, or returns `Ok(0)` if none exists.
-    fn write_vectored_at_volatile(&mut self, bufs: &[VolatileSlice], offset: u64) -> Result<usize> {
-        if let Some(&slice) = bufs.first() {
-            self.write_at_volatile(slice, offset)
-        } else {
-            Ok(0)
+    /// concatenated would. The default implementation calls `write_at_volatile` once per buffer,
+    /// advancing `offset` by the number of bytes actually written each time, and stops at the first
+    /// buffer that is written only partially (or not at all).
+    fn write_vectored_at_volatile(&mut self, bufs: &[VolatileSlice], mut offset: u64) -> Result<usize> {
+        let mut total = 0;
+        for &slice in bufs {
+            if slice.is_empty() {
+                continue;
+            }
+            let n = self.write_at_volatile(slice, offset)?;
+            total += n;
+            offset = offset.checked_add(n as u64).unwrap();
+            if n < slice.len() {
+                break;
+            }
         }
+        Ok(total)
     }
 
     /// Writes bytes from this file at `offset` into the given slice until all bytes in the slice
@@ -216,93 +325,166 @@ impl<'a, T: FileReadWriteAtVolatile + ?Sized> FileReadWriteAtVolatile for &'a mu
     }
 }
 
+/// `readv`/`writev`/`preadv64`/`pwritev64` take iovec counts this small on virtio's hot path
+/// (a handful of descriptors per request) far more often than not, so that many fit inline on
+/// the stack in `IovecBuf::Inline` and only a longer chain spills to `IovecBuf::Heap`.
+const INLINE_IOVECS: usize = 8;
+
+/// The `libc::iovec` array built from a slice of `VolatileSlice`, either inline on the stack
+/// (the common case) or on the heap when there are more than `INLINE_IOVECS` buffers.
+pub enum IovecBuf {
+    Inline([libc::iovec; INLINE_IOVECS], usize),
+    Heap(Vec<libc::iovec>),
+}
+
+impl IovecBuf {
+    pub fn as_slice(&self) -> &[libc::iovec] {
+        match self {
+            IovecBuf::Inline(iovecs, len) => &iovecs[..*len],
+            IovecBuf::Heap(iovecs) => iovecs,
+        }
+    }
+}
+
+/// Builds the `libc::iovec`s that `readv`/`writev`/`preadv64`/`pwritev64` expect from a slice
+/// of `VolatileSlice`, without allocating for the common small-count case.
+///
+/// `VolatileSlice` is a foreign type from the `vm_memory` crate: it isn't `#[repr(C)]`, and
+/// upstream it carries a bitmap tracker and a `PhantomData` alongside the pointer/length
+/// pair, so its layout and size aren't guaranteed to match `struct iovec` and a `&[VolatileSlice]`
+/// can't be reinterpreted as `&[libc::iovec]`. This has to copy `as_ptr()`/`len()` out of each
+/// slice explicitly instead.
+pub fn to_iovecs(bufs: &[VolatileSlice]) -> IovecBuf {
+    let iovec = |b: &VolatileSlice| libc::iovec {
+        iov_base: b.as_ptr() as *mut libc::c_void,
+        iov_len: b.len(),
+    };
+
+    if bufs.len() <= INLINE_IOVECS {
+        // `std::array::from_fn` builds each element in place, so this doesn't require
+        // `libc::iovec: Copy` the way a `[iovec::default(); N]` repeat expression would.
+        let iovecs: [libc::iovec; INLINE_IOVECS] = std::array::from_fn(|i| {
+            bufs.get(i)
+                .map(&iovec)
+                .unwrap_or(libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 })
+        });
+        IovecBuf::Inline(iovecs, bufs.len())
+    } else {
+        IovecBuf::Heap(bufs.iter().map(iovec).collect())
+    }
+}
+
+/// Implements `FileReadWriteVolatile` for a file-like type backed by a raw fd.
+///
+/// Only stream (non-positioned) reads/writes are generated, so this can be used for
+/// descriptors that don't support `pread`/`pwrite` semantics, such as tap devices,
+/// sockets, pipes and eventfds. Also invoke `volatile_at_impl!` (as is done for `File`)
+/// when the descriptor supports positioned I/O.
+#[macro_export]
 macro_rules! volatile_impl {
     ($ty:ty) => {
-        impl FileReadWriteVolatile for $ty {
-            fn read_volatile(&mut self, slice: VolatileSlice) -> Result<usize> {
+        impl $crate::virtio::fs::file_traits::FileReadWriteVolatile for $ty {
+            fn read_volatile(&mut self, slice: ::vm_memory::VolatileSlice) -> ::std::io::Result<usize> {
                 // Safe because only bytes inside the slice are accessed and the kernel is expected
                 // to handle arbitrary memory for I/O.
-                let ret =
-                    unsafe { read(self.as_raw_fd(), slice.as_ptr() as *mut c_void, slice.len()) };
+                let ret = unsafe {
+                    ::libc::read(
+                        self.as_raw_fd(),
+                        slice.as_ptr() as *mut ::libc::c_void,
+                        slice.len(),
+                    )
+                };
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
 
-            fn read_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> Result<usize> {
-                let iovecs: Vec<libc::iovec> = bufs
-                    .iter()
-                    .map(|s| libc::iovec {
-                        iov_base: s.as_ptr() as *mut c_void,
-                        iov_len: s.len() as size_t,
-                    })
-                    .collect();
-
+            fn read_vectored_volatile(
+                &mut self,
+                bufs: &[::vm_memory::VolatileSlice],
+            ) -> ::std::io::Result<usize> {
+                let iovec_buf = $crate::virtio::fs::file_traits::to_iovecs(bufs);
+                let iovecs = iovec_buf.as_slice();
                 if iovecs.is_empty() {
                     return Ok(0);
                 }
 
                 // Safe because only bytes inside the buffers are accessed and the kernel is
                 // expected to handle arbitrary memory for I/O.
-                let ret = unsafe { readv(self.as_raw_fd(), &iovecs[0], iovecs.len() as c_int) };
+                let ret = unsafe {
+                    ::libc::readv(self.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as ::libc::c_int)
+                };
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
 
-            fn write_volatile(&mut self, slice: VolatileSlice) -> Result<usize> {
+            fn write_volatile(&mut self, slice: ::vm_memory::VolatileSlice) -> ::std::io::Result<usize> {
                 // Safe because only bytes inside the slice are accessed and the kernel is expected
                 // to handle arbitrary memory for I/O.
                 let ret = unsafe {
-                    write(
+                    ::libc::write(
                         self.as_raw_fd(),
-                        slice.as_ptr() as *const c_void,
+                        slice.as_ptr() as *const ::libc::c_void,
                         slice.len(),
                     )
                 };
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
 
-            fn write_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> Result<usize> {
-                let iovecs: Vec<libc::iovec> = bufs
-                    .iter()
-                    .map(|s| libc::iovec {
-                        iov_base: s.as_ptr() as *mut c_void,
-                        iov_len: s.len() as size_t,
-                    })
-                    .collect();
-
+            fn write_vectored_volatile(
+                &mut self,
+                bufs: &[::vm_memory::VolatileSlice],
+            ) -> ::std::io::Result<usize> {
+                let iovec_buf = $crate::virtio::fs::file_traits::to_iovecs(bufs);
+                let iovecs = iovec_buf.as_slice();
                 if iovecs.is_empty() {
                     return Ok(0);
                 }
 
                 // Safe because only bytes inside the buffers are accessed and the kernel is
                 // expected to handle arbitrary memory for I/O.
-                let ret = unsafe { writev(self.as_raw_fd(), &iovecs[0], iovecs.len() as c_int) };
+                let ret = unsafe {
+                    ::libc::writev(self.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as ::libc::c_int)
+                };
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
         }
+    };
+}
 
-        impl FileReadWriteAtVolatile for $ty {
-            fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> Result<usize> {
+/// Implements `FileReadWriteAtVolatile` for a file-like type backed by a raw fd.
+///
+/// This is separate from `volatile_impl!` because positioned I/O (`pread`/`pwrite`) is
+/// only meaningful for seekable descriptors; non-seekable ones (tap devices, sockets,
+/// pipes, eventfds) should only derive `FileReadWriteVolatile`.
+#[macro_export]
+macro_rules! volatile_at_impl {
+    ($ty:ty) => {
+        impl $crate::virtio::fs::file_traits::FileReadWriteAtVolatile for $ty {
+            fn read_at_volatile(
+                &mut self,
+                slice: ::vm_memory::VolatileSlice,
+                offset: u64,
+            ) -> ::std::io::Result<usize> {
                 // Safe because only bytes inside the slice are accessed and the kernel is expected
                 // to handle arbitrary memory for I/O.
                 let ret = unsafe {
                     pread64(
                         self.as_raw_fd(),
-                        slice.as_ptr() as *mut c_void,
+                        slice.as_ptr() as *mut ::libc::c_void,
                         slice.len(),
                         offset as off64_t,
                     )
@@ -311,23 +493,17 @@ macro_rules! volatile_impl {
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
 
             fn read_vectored_at_volatile(
                 &mut self,
-                bufs: &[VolatileSlice],
+                bufs: &[::vm_memory::VolatileSlice],
                 offset: u64,
-            ) -> Result<usize> {
-                let iovecs: Vec<libc::iovec> = bufs
-                    .iter()
-                    .map(|s| libc::iovec {
-                        iov_base: s.as_ptr() as *mut c_void,
-                        iov_len: s.len() as size_t,
-                    })
-                    .collect();
-
+            ) -> ::std::io::Result<usize> {
+                let iovec_buf = $crate::virtio::fs::file_traits::to_iovecs(bufs);
+                let iovecs = iovec_buf.as_slice();
                 if iovecs.is_empty() {
                     return Ok(0);
                 }
@@ -337,26 +513,30 @@ macro_rules! volatile_impl {
                 let ret = unsafe {
                     preadv64(
                         self.as_raw_fd(),
-                        &iovecs[0],
-                        iovecs.len() as c_int,
+                        iovecs.as_ptr(),
+                        iovecs.len() as ::libc::c_int,
                         offset as off64_t,
                     )
                 };
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
 
-            fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> Result<usize> {
+            fn write_at_volatile(
+                &mut self,
+                slice: ::vm_memory::VolatileSlice,
+                offset: u64,
+            ) -> ::std::io::Result<usize> {
                 // Safe because only bytes inside the slice are accessed and the kernel is expected
                 // to handle arbitrary memory for I/O.
                 // unsafe {lseek64(self.as_raw_fd(),offset as off64_t,libc::SEEK_SET);}
                 let ret = unsafe {
                     pcwrite(
                         self.as_raw_fd(),
-                        slice.as_ptr() as *const c_void,
+                        slice.as_ptr() as *const ::libc::c_void,
                         slice.len(),
                         offset as off64_t
                     )
@@ -365,7 +545,7 @@ macro_rules! volatile_impl {
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
 
@@ -405,40 +585,31 @@ macro_rules! volatile_impl {
 
             fn write_vectored_at_volatile(
                 &mut self,
-                bufs: &[VolatileSlice],
+                bufs: &[::vm_memory::VolatileSlice],
                 offset: u64,
-            ) -> Result<usize> {
-                let iovecs: Vec<libc::iovec> = bufs
-                    .iter()
-                    .map(|s| libc::iovec {
-                        iov_base: s.as_ptr() as *mut c_void,
-                        iov_len: s.len() as size_t,
-                    })
-                    .collect();
-
+            ) -> ::std::io::Result<usize> {
+                let iovec_buf = $crate::virtio::fs::file_traits::to_iovecs(bufs);
+                let iovecs = iovec_buf.as_slice();
                 if iovecs.is_empty() {
                     return Ok(0);
                 }
 
-                // Safe because only bytes inside the buffers are accessed and the kernel is
-                // expected to handle arbitrary memory for I/O.
-                unsafe {lseek64(self.as_raw_fd(),offset as off64_t,libc::SEEK_SET);}
-                let mut ret :isize = 0; 
-                for ivc in iovecs {
-                   let tmp = unsafe {
-                    cwrite(
+                // Safe because only bytes inside the buffers are accessed, the kernel is expected
+                // to handle arbitrary memory for I/O, and `pwritev64` writes at `offset` without
+                // touching the file's seek position, unlike an `lseek64` + `write` pair.
+                let ret = unsafe {
+                    pwritev64(
                         self.as_raw_fd(),
-                        ivc.iov_base as *const libc::c_void,
-                        ivc.iov_len as usize
+                        iovecs.as_ptr(),
+                        iovecs.len() as ::libc::c_int,
+                        offset as off64_t,
                     )
-                   };
-                   ret = ret + tmp ;
-                }
-                
+                };
+
                 if ret >= 0 {
                     Ok(ret as usize)
                 } else {
-                    Err(Error::last_os_error())
+                    Err(::std::io::Error::last_os_error())
                 }
             }
             // fn write_vectored_at_volatile(
@@ -491,3 +662,76 @@ macro_rules! volatile_impl {
 }
 
 volatile_impl!(File);
+volatile_at_impl!(File);
+
+/// Reads/writes guest memory straight from/to a `VolatileSlice`, without staging the data
+/// through an intermediate host buffer. `offset` is the byte offset into the region, and
+/// is bounds-checked against the region's length. There is no meaningful stream position
+/// for a memory region, so the non-offset `FileReadWriteVolatile` methods below just read
+/// or write starting at offset `0`.
+impl FileReadWriteAtVolatile for vm_memory::GuestRegionMmap {
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> Result<usize> {
+        let region = self
+            .as_volatile_slice()
+            .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+        let offset: usize = offset.try_into().map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+        if offset > region.len() {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        let len = std::cmp::min(slice.len(), region.len() - offset);
+        if len == 0 {
+            return Ok(0);
+        }
+        let src = region.offset(offset).unwrap();
+        // Safe because `len` was clamped to both the source region and the destination slice.
+        unsafe {
+            memcpy(
+                slice.as_ptr() as *mut c_void,
+                src.as_ptr() as *const c_void,
+                len,
+            );
+        }
+        Ok(len)
+    }
+
+    // `read_vectored_at_volatile` is not overridden here: the trait default (walk each
+    // buffer through `read_at_volatile`, advancing `offset`, stopping on a short read) is
+    // exactly what this impl would otherwise duplicate.
+
+    fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> Result<usize> {
+        let region = self
+            .as_volatile_slice()
+            .map_err(|_| Error::from(ErrorKind::WriteZero))?;
+        let offset: usize = offset.try_into().map_err(|_| Error::from(ErrorKind::WriteZero))?;
+        if offset > region.len() {
+            return Err(Error::from(ErrorKind::WriteZero));
+        }
+        let len = std::cmp::min(slice.len(), region.len() - offset);
+        if len == 0 {
+            return Ok(0);
+        }
+        let dst = region.offset(offset).unwrap();
+        // Safe because `len` was clamped to both the destination region and the source slice.
+        unsafe {
+            memcpy(
+                dst.as_ptr() as *mut c_void,
+                slice.as_ptr() as *const c_void,
+                len,
+            );
+        }
+        Ok(len)
+    }
+
+    // `write_vectored_at_volatile` is not overridden here for the same reason as
+    // `read_vectored_at_volatile` above: the trait default already does this correctly.
+}
+
+impl FileReadWriteVolatile for vm_memory::GuestRegionMmap {
+    fn read_volatile(&mut self, slice: VolatileSlice) -> Result<usize> {
+        self.read_at_volatile(slice, 0)
+    }
+
+    fn write_volatile(&mut self, slice: VolatileSlice) -> Result<usize> {
+        self.write_at_volatile(slice, 0)
+    }
+}
@@ -1,85 +1,816 @@
+extern crate libc;
 extern crate seccomp;
-// extern crate libc;
 extern crate syscalls;
 
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
 use self::seccomp::*;
-use self::syscalls::SyscallNo::*;
+use self::syscalls::SyscallNo;
+
 pub fn create_default_seccomp_rule(sysno: usize) -> self::seccomp::Rule {
     let rule = Rule::new(sysno,
             Compare::arg(0)
                     .with(0)
                     .using(Op::Ge)
                     .build().unwrap(),
-            Action::Allow 
+            Action::Allow
         );
     return rule;
 }
 
+/// One `(arg_index, op, value)` comparison on a single syscall argument.
+pub struct ArgConstraint {
+    arg_index: u32,
+    op: Op,
+    value: u64,
+}
+
+impl ArgConstraint {
+    pub fn new(arg_index: u32, op: Op, value: u64) -> Self {
+        ArgConstraint { arg_index, op, value }
+    }
+
+    fn compile(&self) -> Compare {
+        Compare::arg(self.arg_index)
+            .with(self.value)
+            .using(self.op)
+            .build()
+            .unwrap()
+    }
+}
+
+/// One allowed argument shape for a syscall: every constraint must hold (AND).
+pub struct ArgRule {
+    constraints: Vec<ArgConstraint>,
+}
+
+impl ArgRule {
+    pub fn new(constraints: Vec<ArgConstraint>) -> Self {
+        ArgRule { constraints }
+    }
+}
+
+/// A syscall plus the alternative argument shapes (OR-ed `ArgRule`s) allowed for it. A
+/// `SyscallRule` with no `ArgRule`s at all falls back to the unconditional allow produced by
+/// `create_default_seccomp_rule`.
+pub struct SyscallRule {
+    sysno: usize,
+    rule_sets: Vec<ArgRule>,
+}
+
+impl SyscallRule {
+    pub fn new(sysno: SyscallNo, rule_sets: Vec<ArgRule>) -> Self {
+        SyscallRule { sysno: sysno as usize, rule_sets }
+    }
+
+    /// Compiles this description into the concrete `seccomp::Rule`s that implement it: one per
+    /// alternative argument shape, since libseccomp ORs together multiple rules added for the
+    /// same syscall number.
+    fn compile(&self) -> Vec<Rule> {
+        if self.rule_sets.is_empty() {
+            return vec![create_default_seccomp_rule(self.sysno)];
+        }
+
+        self.rule_sets
+            .iter()
+            .map(|rule_set| {
+                let compares: Vec<Compare> =
+                    rule_set.constraints.iter().map(ArgConstraint::compile).collect();
+                Rule::new(self.sysno, compares, Action::Allow)
+            })
+            .collect()
+    }
+}
+
+// Per-architecture syscall number tables. `syscalls::SyscallNo::SYS_*` resolves to the numeric
+// ABI of whatever target it's named for, so a single list built for x86_64 silently carries the
+// wrong numbers (or names that don't exist at all, e.g. there is no bare `fstat`/`epoll_wait` on
+// aarch64) when compiled for another architecture. Splitting the table per `target_arch`, like
+// rustix does for its own syscall backends, means the compiler itself rejects a name that
+// doesn't resolve on a given arch -- each table below only compiles at all if every entry in it
+// is valid for that architecture.
+#[cfg(target_arch = "x86_64")]
+mod arch_syscalls {
+    use super::syscalls::SyscallNo::*;
+
+    pub const ALLOWED: &[usize] = &[
+        SYS_fadvise64 as usize,
+        SYS_fallocate as usize,
+        SYS_fdatasync as usize,
+        SYS_fcntl as usize,
+        SYS_fstat as usize,
+        SYS_ftruncate as usize,
+        SYS_preadv as usize,
+        SYS_pwritev as usize,
+        SYS_lseek as usize,
+        SYS_fsync as usize,
+        SYS_mkdirat as usize,
+        SYS_linkat as usize,
+        SYS_openat as usize,
+        SYS_readlinkat as usize,
+        SYS_unlinkat as usize,
+        SYS_symlinkat as usize,
+        SYS_getrandom as usize,
+        SYS_utimensat as usize,
+        SYS_renameat2 as usize,
+        SYS_connect as usize,
+        SYS_recvfrom as usize,
+        SYS_sendto as usize,
+        SYS_shutdown as usize,
+        SYS_getpeername as usize,
+        SYS_futex as usize,
+        SYS_read as usize,
+        SYS_write as usize,
+        SYS_epoll_wait as usize,
+        SYS_epoll_ctl as usize,
+        SYS_newfstatat as usize,
+        SYS_umask as usize,
+        SYS_brk as usize,
+        SYS_close as usize,
+        SYS_dup as usize,
+        SYS_fgetxattr as usize,
+        SYS_madvise as usize,
+        SYS_exit_group as usize,
+        SYS_getdents64 as usize,
+        SYS_fchownat as usize,
+    ];
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch_syscalls {
+    use super::syscalls::SyscallNo::*;
+
+    // aarch64 has no legacy `fstat` or `epoll_wait` syscalls (it only ever shipped the `*at`
+    // and `*_pwait` forms), so those two are dropped here; `newfstatat` already covers the
+    // `fstat` case for the rest of the table.
+    pub const ALLOWED: &[usize] = &[
+        SYS_fadvise64 as usize,
+        SYS_fallocate as usize,
+        SYS_fdatasync as usize,
+        SYS_fcntl as usize,
+        SYS_ftruncate as usize,
+        SYS_preadv as usize,
+        SYS_pwritev as usize,
+        SYS_lseek as usize,
+        SYS_fsync as usize,
+        SYS_mkdirat as usize,
+        SYS_linkat as usize,
+        SYS_openat as usize,
+        SYS_readlinkat as usize,
+        SYS_unlinkat as usize,
+        SYS_symlinkat as usize,
+        SYS_getrandom as usize,
+        SYS_utimensat as usize,
+        SYS_renameat2 as usize,
+        SYS_connect as usize,
+        SYS_recvfrom as usize,
+        SYS_sendto as usize,
+        SYS_shutdown as usize,
+        SYS_getpeername as usize,
+        SYS_futex as usize,
+        SYS_read as usize,
+        SYS_write as usize,
+        SYS_epoll_pwait as usize,
+        SYS_epoll_ctl as usize,
+        SYS_newfstatat as usize,
+        SYS_umask as usize,
+        SYS_brk as usize,
+        SYS_close as usize,
+        SYS_dup as usize,
+        SYS_fgetxattr as usize,
+        SYS_madvise as usize,
+        SYS_exit_group as usize,
+        SYS_getdents64 as usize,
+        SYS_fchownat as usize,
+    ];
+}
+
+#[cfg(test)]
+mod arch_syscalls_tests {
+    // The real guarantee here is that `arch_syscalls::ALLOWED` compiles for the host
+    // architecture at all: a typo'd or wrong-arch syscall name is a build break, not
+    // something a runtime assertion would catch. Keeping a second hand-maintained copy
+    // of the table alongside it just to `assert_eq!` the two only verifies they match
+    // each other, not that either is complete, so there's nothing to duplicate here.
+    #[test]
+    fn table_is_non_empty() {
+        assert!(!super::arch_syscalls::ALLOWED.is_empty());
+    }
+}
+
+// Request codes from `<linux/kvm.h>` and `<linux/vhost.h>` that libkrun actually issues.
+// `ioctl`'s request number is its second argument (arg index 1).
+const KVM_RUN: u64 = 0xae80;
+const KVM_CREATE_VCPU: u64 = 0xae41;
+const KVM_GET_REGS: u64 = 0x8090ae81;
+const KVM_SET_REGS: u64 = 0x4090ae82;
+const KVM_GET_SREGS: u64 = 0x8138ae83;
+const KVM_SET_SREGS: u64 = 0x4138ae84;
+const KVM_IRQ_LINE: u64 = 0x4008ae61;
+const KVM_SET_USER_MEMORY_REGION: u64 = 0x4020ae46;
+const VHOST_SET_MEM_TABLE: u64 = 0x4008af03;
+const VHOST_SET_VRING_KICK: u64 = 0x4008af20;
+const VHOST_SET_VRING_CALL: u64 = 0x4008af21;
+
+const ALLOWED_IOCTLS: &[u64] = &[
+    KVM_RUN,
+    KVM_CREATE_VCPU,
+    KVM_GET_REGS,
+    KVM_SET_REGS,
+    KVM_GET_SREGS,
+    KVM_SET_SREGS,
+    KVM_IRQ_LINE,
+    KVM_SET_USER_MEMORY_REGION,
+    VHOST_SET_MEM_TABLE,
+    VHOST_SET_VRING_KICK,
+    VHOST_SET_VRING_CALL,
+];
+
+fn ioctl_rule() -> SyscallRule {
+    SyscallRule::new(
+        SyscallNo::SYS_ioctl,
+        ALLOWED_IOCTLS
+            .iter()
+            .map(|&req| ArgRule::new(vec![ArgConstraint::new(1, Op::Eq, req)]))
+            .collect(),
+    )
+}
+
+fn socket_rule() -> SyscallRule {
+    // Only the address families the vsock/net backends actually use.
+    const ALLOWED_FAMILIES: &[u64] = &[
+        libc::AF_INET as u64,
+        libc::AF_INET6 as u64,
+        libc::AF_UNIX as u64,
+        AF_VSOCK as u64,
+    ];
+    SyscallRule::new(
+        SyscallNo::SYS_socket,
+        ALLOWED_FAMILIES
+            .iter()
+            .map(|&family| ArgRule::new(vec![ArgConstraint::new(0, Op::Eq, family)]))
+            .collect(),
+    )
+}
+
+fn mmap_like_rule(sysno: SyscallNo, prot_arg_index: u32) -> SyscallRule {
+    // `prot` is only 3 meaningful low bits (READ=1, WRITE=2, EXEC=4); reject the two values
+    // that have both WRITE and EXEC set (6, 7) by only allowing the other six explicitly,
+    // instead of allowing `prot` unconditionally like `create_default_seccomp_rule` would.
+    const ALLOWED_PROTS: &[u64] = &[0, 1, 2, 3, 4, 5];
+    SyscallRule::new(
+        sysno,
+        ALLOWED_PROTS
+            .iter()
+            .map(|&prot| ArgRule::new(vec![ArgConstraint::new(prot_arg_index, Op::Eq, prot)]))
+            .collect(),
+    )
+}
+
+fn clone_rule() -> SyscallRule {
+    // Only allow `clone` with the exact flag combination `pthread_create` uses for spawning a
+    // new thread inside the current process, not flags that would fork a new process.
+    const THREAD_CLONE_FLAGS: u64 = (libc::CLONE_VM
+        | libc::CLONE_FS
+        | libc::CLONE_FILES
+        | libc::CLONE_SIGHAND
+        | libc::CLONE_THREAD
+        | libc::CLONE_SYSVSEM
+        | libc::CLONE_SETTLS
+        | libc::CLONE_PARENT_SETTID
+        | libc::CLONE_CHILD_CLEARTID) as u64;
+    SyscallRule::new(
+        SyscallNo::SYS_clone,
+        vec![ArgRule::new(vec![ArgConstraint::new(0, Op::Eq, THREAD_CLONE_FLAGS)])],
+    )
+}
+
+/// What happens to a thread that reaches a syscall not covered by the default allowlist.
+///
+/// `Log` and `Trace` exist so a new device or filesystem path can be profiled without the
+/// workload dying at the very first unlisted syscall: run once in one of those modes, see (or
+/// collect) exactly which syscalls were reached, add the missing ones to
+/// `arch_syscalls::ALLOWED`, then switch back to `Kill` to lock the policy down again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeccompMode {
+    /// Kill the whole process immediately. The only mode before this was added.
+    Kill,
+    /// Print the offending syscall number, faulting PC and argument registers to stderr as
+    /// each one is hit, then fail it with `ENOSYS` instead of killing the thread.
+    Log,
+    /// Like `Log`, but silently record each distinct syscall number instead of printing it;
+    /// drain the set afterward with `take_observed_syscalls()`.
+    Trace,
+}
+
+impl SeccompMode {
+    fn as_u8(self) -> u8 {
+        match self {
+            SeccompMode::Kill => 0,
+            SeccompMode::Log => 1,
+            SeccompMode::Trace => 2,
+        }
+    }
+}
+
+/// Which `SeccompMode` the installed `SIGSYS` handler should behave as; `0` means the handler
+/// was never installed (`Kill` mode doesn't use `SIGSYS` at all).
+static ACTIVE_MODE: AtomicU8 = AtomicU8::new(0);
+
+/// Syscall numbers seen by the `SIGSYS` handler while running in `SeccompMode::Trace`.
+static OBSERVED_SYSCALLS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Returns and clears the syscall numbers collected so far in `SeccompMode::Trace`.
+pub fn take_observed_syscalls() -> Vec<usize> {
+    std::mem::take(&mut *OBSERVED_SYSCALLS.lock().unwrap())
+}
+
+/// The `{ call_addr, syscall, arch }` triple the kernel fills in `siginfo_t`'s `_sigsys` field
+/// for a `SECCOMP_RET_TRAP` denial (see `_sigsys` in `<bits/siginfo-consts.h>`). It starts right
+/// after the common `si_signo`/`si_errno`/`si_code` header and its trailing padding word, at
+/// byte offset 16 of `siginfo_t` on both x86_64 and aarch64 Linux.
+#[repr(C)]
+struct Sigsys {
+    call_addr: *mut libc::c_void,
+    syscall: libc::c_int,
+    arch: libc::c_uint,
+}
+
+/// `SIGSYS` handler installed for `Log`/`Trace` mode. Logs or records the denied syscall
+/// depending on `ACTIVE_MODE`, then fails it with `ENOSYS` by patching the faulting thread's
+/// saved return register, so the thread gets an ordinary-looking error return instead of
+/// being killed outright by the default `SIGSYS` disposition. This does not perform the real
+/// syscall -- only `SECCOMP_RET_TRACE` plus a `ptrace` tracer could do that -- and it
+/// deliberately fails with `ENOSYS` rather than faking a `0` success: `0` is a valid-looking
+/// return for some of the calls this filter cares about (`mmap` returning `0` reads as a
+/// successful mapping at address `NULL`), so faking it would risk corrupting the workload's
+/// control flow instead of just failing the call cleanly. `Log`/`Trace` exist to see which
+/// syscalls a workload reaches while developing a policy, not to let it run unaffected.
+extern "C" fn sigsys_handler(_signum: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    // Safe because the kernel only delivers this handler a `siginfo_t` for a `SECCOMP_RET_TRAP`
+    // `SIGSYS`, which always has the `_sigsys` field populated at this offset.
+    let sigsys = unsafe { &*(info as *const u8).add(16).cast::<Sigsys>() };
+    let syscall = sigsys.syscall as usize;
+
+    match ACTIVE_MODE.load(Ordering::Relaxed) {
+        1 => {
+            // Safe for the same reason as `fail_syscall_with_errno` below: `ctx` is the
+            // `ucontext_t *` the kernel passed this `SA_SIGINFO` handler.
+            let args = unsafe { syscall_args(ctx) };
+            eprintln!(
+                "seccomp: denied syscall {} at pc {:?}, args {:?} (failing with ENOSYS)",
+                syscall, sigsys.call_addr, args
+            );
+        }
+        2 => {
+            let mut observed = OBSERVED_SYSCALLS.lock().unwrap();
+            if !observed.contains(&syscall) {
+                observed.push(syscall);
+            }
+        }
+        _ => {}
+    }
+
+    // Safe because `ctx` is the valid `ucontext_t *` the kernel passed to this `SA_SIGINFO`
+    // handler.
+    unsafe { fail_syscall_with_errno(ctx, libc::ENOSYS) };
+}
+
+/// Reads the six general-purpose syscall argument registers out of a trapped thread's
+/// `ucontext_t*`, in syscall-argument order, for the `Log` mode argument dump.
+#[cfg(target_arch = "x86_64")]
+unsafe fn syscall_args(ctx: *mut libc::c_void) -> [u64; 6] {
+    let gregs = &(*(ctx as *const libc::ucontext_t)).uc_mcontext.gregs;
+    [
+        gregs[libc::REG_RDI as usize] as u64,
+        gregs[libc::REG_RSI as usize] as u64,
+        gregs[libc::REG_RDX as usize] as u64,
+        gregs[libc::REG_R10 as usize] as u64,
+        gregs[libc::REG_R8 as usize] as u64,
+        gregs[libc::REG_R9 as usize] as u64,
+    ]
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn syscall_args(ctx: *mut libc::c_void) -> [u64; 6] {
+    let regs = &(*(ctx as *const libc::ucontext_t)).uc_mcontext.regs;
+    [regs[0], regs[1], regs[2], regs[3], regs[4], regs[5]]
+}
+
+/// Patches a trapped thread's saved return register so the syscall it was denied comes back
+/// as `-errno`, matching the raw kernel syscall ABI (the libc syscall wrapper that resumes
+/// afterward turns a negative return into `errno` plus a `-1`/`NULL`/`MAP_FAILED` result, as
+/// appropriate for that call) instead of a success value that could be misread by the caller.
+#[cfg(target_arch = "x86_64")]
+unsafe fn fail_syscall_with_errno(ctx: *mut libc::c_void, errno: i32) {
+    let ucontext = &mut *(ctx as *mut libc::ucontext_t);
+    ucontext.uc_mcontext.gregs[libc::REG_RAX as usize] = -(errno as i64);
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn fail_syscall_with_errno(ctx: *mut libc::c_void, errno: i32) {
+    let ucontext = &mut *(ctx as *mut libc::ucontext_t);
+    ucontext.uc_mcontext.regs[0] = (-(errno as i64)) as u64;
+}
+
+fn install_sigsys_handler() {
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = sigsys_handler as usize;
+        sa.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut sa.sa_mask);
+        libc::sigaction(libc::SIGSYS, &sa, std::ptr::null_mut());
+    }
+}
+
+/// Builds the allowlist all of `add_seccomp_filter_with_mode` and
+/// `add_seccomp_filter_with_broker` share: the per-arch stream syscalls plus the
+/// argument-constrained `ioctl`/`socket`/`mmap`/`mprotect`/`clone` rules.
+///
+/// `exclude` drops syscall numbers out of the unconditional `arch_syscalls::ALLOWED` table
+/// before it's added -- callers that route a syscall to the notify broker instead (see
+/// `add_seccomp_filter_with_broker`) must list it here, or the unconditional allow rule
+/// would match first and the call would never reach the broker at all.
+fn build_base_context(default_action: Action, exclude: &[usize]) -> Context {
+    let mut ctx = Context::default(default_action).unwrap();
+
+    for &sysno in arch_syscalls::ALLOWED {
+        if exclude.contains(&sysno) {
+            continue;
+        }
+        ctx.add_rule(create_default_seccomp_rule(sysno)).unwrap();
+    }
+
+    for rule in [
+        ioctl_rule(),
+        socket_rule(),
+        mmap_like_rule(SyscallNo::SYS_mmap, 2),
+        mmap_like_rule(SyscallNo::SYS_mprotect, 2),
+        clone_rule(),
+    ] {
+        for compiled in rule.compile() {
+            ctx.add_rule(compiled).unwrap();
+        }
+    }
+
+    ctx
+}
+
+/// Like `add_seccomp_filter`, but lets the caller pick the default action instead of always
+/// killing the process on a denied syscall.
+pub fn add_seccomp_filter_with_mode(mode: SeccompMode) {
+    let default_action = match mode {
+        SeccompMode::Kill => Action::KillProcess,
+        SeccompMode::Log | SeccompMode::Trace => {
+            install_sigsys_handler();
+            Action::Trap
+        }
+    };
+    ACTIVE_MODE.store(mode.as_u8(), Ordering::Relaxed);
+
+    build_base_context(default_action, &[]).load().unwrap();
+}
+
 pub fn add_seccomp_filter() {
-    //add seccomp filter
-    let mut ctx = Context::default(Action::KillProcess).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_rt_sigaction as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_mmap as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_statx as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_msync as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_msync as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_rt_sigprocmask as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_clone as usize)).unwrap();
-
-    // ctx.add_rule(create_default_seccomp_rule(SYS_clock_getres as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_clock_gettime as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fadvise64 as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fallocate as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fdatasync as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fcntl as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fstat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_ftruncate as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_preadv as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_pwritev as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_readv as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_lseek as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fsync as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_writev as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_mkdirat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_linkat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_openat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_readlinkat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_unlinkat as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_renameat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_symlinkat as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_clock_nanosleep as usize)).unwrap();
-    // ctx.add_rule(create_default_seccomp_rule(SYS_sched_yield as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_getrandom as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_utimensat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_renameat2 as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_socket as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_connect as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_recvfrom as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_sendto as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_shutdown as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_getpeername as usize)).unwrap();
-    
-
-
-
-
-    ctx.add_rule(create_default_seccomp_rule(SYS_futex as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_read as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_write as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_epoll_wait as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_epoll_ctl as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_newfstatat as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_umask as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_brk as usize)).unwrap();
-    
-    
-    ctx.add_rule(create_default_seccomp_rule(SYS_close as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_dup as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fgetxattr as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_madvise as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_exit_group as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_getdents64 as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_ioctl as usize)).unwrap();
-    ctx.add_rule(create_default_seccomp_rule(SYS_fchownat as usize)).unwrap();
-    ctx.load().unwrap();
+    add_seccomp_filter_with_mode(SeccompMode::Kill);
+}
+
+// --- User-notification broker for syscalls a BPF comparison can't fully constrain ---
+//
+// `ioctl`/`socket`/`mmap` etc. above are bounded entirely by comparing integer arguments,
+// which is all BPF can do. `openat` and `connect` aren't: the argument that matters is a
+// *pointer* into the calling thread's address space (a path string, a `sockaddr`), and BPF
+// has no way to dereference it. `SECCOMP_RET_USER_NOTIF` hands those two syscalls to this
+// process instead -- the kernel parks the calling thread and waits for us to read its
+// arguments out of `/proc/<pid>/mem`, decide, and reply with either "continue" or a chosen
+// errno.
+
+/// Kernel ABI mirror of `struct seccomp_data` from `<linux/seccomp.h>`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SeccompData {
+    nr: i32,
+    arch: u32,
+    instruction_pointer: u64,
+    args: [u64; 6],
+}
+
+/// Kernel ABI mirror of `struct seccomp_notif` from `<linux/seccomp.h>`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SeccompNotif {
+    id: u64,
+    pid: u32,
+    flags: u32,
+    data: SeccompData,
+}
+
+/// Kernel ABI mirror of `struct seccomp_notif_resp` from `<linux/seccomp.h>`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SeccompNotifResp {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+/// `SECCOMP_USER_NOTIF_FLAG_CONTINUE`: tell the kernel to run the syscall with its original
+/// arguments instead of returning `resp.val`/`resp.error`.
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+
+// `_IOWR('!', 0, struct seccomp_notif)` / `_IOWR('!', 1, struct seccomp_notif_resp)` /
+// `_IOW('!', 2, __u64)` from `<linux/seccomp.h>`. libc doesn't expose these yet, so they're
+// spelled out the same way the KVM/vhost ioctl request codes above are.
+const SECCOMP_IOCTL_NOTIF_RECV: libc::c_ulong = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: libc::c_ulong = 0xc018_2101;
+const SECCOMP_IOCTL_NOTIF_ID_VALID: libc::c_ulong = 0x4008_2102;
+
+/// Routes `sysno` to the userspace broker instead of a static allow/kill, mirroring
+/// `create_default_seccomp_rule` but with `Action::Notify`.
+fn create_notify_rule(sysno: usize) -> Rule {
+    Rule::new(
+        sysno,
+        Compare::arg(0).with(0).using(Op::Ge).build().unwrap(),
+        Action::Notify,
+    )
+}
+
+/// What the broker decided to do with one notification.
+enum NotifyDecision {
+    /// Let the syscall run with its original arguments.
+    Continue,
+    /// Fail the syscall with this errno instead of running it.
+    Errno(i32),
+}
+
+/// One `connect` destination `decide_connect` is allowed to let through.
+#[derive(Clone, PartialEq, Eq)]
+pub enum ConnectTarget {
+    Ip(std::net::SocketAddr),
+    Vsock { cid: u32, port: u32 },
+}
+
+/// Host-side policy the broker enforces for the syscalls handed to it.
+pub struct NotifyPolicy {
+    /// `openat` targets below this directory are allowed; anything that escapes it (`..`,
+    /// an absolute path outside it, or a path that simply isn't rooted here) is rejected with
+    /// `EACCES`.
+    pub openat_root: PathBuf,
+    /// IP or vsock peers `connect` is allowed to reach.
+    pub connect_allow: Vec<ConnectTarget>,
+}
+
+impl NotifyPolicy {
+    fn decide(&self, notify_fd: RawFd, notif: &SeccompNotif) -> NotifyDecision {
+        match notif.data.nr as usize {
+            nr if nr == SyscallNo::SYS_openat as usize => self.decide_openat(notify_fd, notif),
+            nr if nr == SyscallNo::SYS_connect as usize => self.decide_connect(notify_fd, notif),
+            _ => NotifyDecision::Errno(libc::ENOSYS),
+        }
+    }
+
+    /// Resolves `dirfd` (the syscall's `arg[0]`) to the directory it refers to in the
+    /// notifying thread. A relative `path` argument is resolved by the kernel against
+    /// `dirfd`, not against `self.openat_root` -- without this check a thread holding an fd
+    /// to any other directory could escape the confined root entirely via
+    /// `openat(other_fd, "passwd")`, even though the path string we read never leaves it.
+    fn resolve_dirfd(&self, notify_fd: RawFd, notif: &SeccompNotif) -> Option<PathBuf> {
+        if !id_still_valid(notify_fd, notif.id) {
+            return None;
+        }
+        let dirfd = notif.data.args[0] as u32 as i32;
+        let link = if dirfd == libc::AT_FDCWD {
+            format!("/proc/{}/cwd", notif.pid)
+        } else {
+            format!("/proc/{}/fd/{}", notif.pid, dirfd)
+        };
+        let target = std::fs::read_link(link).ok()?;
+        if !id_still_valid(notify_fd, notif.id) {
+            return None;
+        }
+        Some(target)
+    }
+
+    fn decide_openat(&self, notify_fd: RawFd, notif: &SeccompNotif) -> NotifyDecision {
+        let path = match read_remote_cstring(notify_fd, notif, notif.data.args[1]) {
+            Some(path) => path,
+            None => return NotifyDecision::Errno(libc::ESRCH),
+        };
+
+        let requested = Path::new(&path);
+        if !requested.is_absolute() {
+            match self.resolve_dirfd(notify_fd, notif) {
+                Some(dir) if dir == self.openat_root => {}
+                _ => return NotifyDecision::Errno(libc::EACCES),
+            }
+        }
+
+        // `self.openat_root.join` doesn't collapse `..`, so walk the components ourselves
+        // rather than `canonicalize`, which would have to touch the filesystem for a path
+        // that may not exist yet (e.g. a file about to be created).
+        let resolved = if requested.is_absolute() {
+            requested.to_path_buf()
+        } else {
+            self.openat_root.join(requested)
+        };
+        let rest = match resolved.strip_prefix(&self.openat_root) {
+            Ok(rest) => rest,
+            Err(_) => return NotifyDecision::Errno(libc::EACCES),
+        };
+        // Depth is counted from `self.openat_root`, so any `..` that climbs above it is rejected.
+        let mut depth: i32 = 0;
+        for component in rest.components() {
+            match component {
+                std::path::Component::ParentDir => depth -= 1,
+                std::path::Component::Normal(_) => depth += 1,
+                _ => {}
+            }
+            if depth < 0 {
+                return NotifyDecision::Errno(libc::EACCES);
+            }
+        }
+
+        NotifyDecision::Continue
+    }
+
+    fn decide_connect(&self, notify_fd: RawFd, notif: &SeccompNotif) -> NotifyDecision {
+        let target = match read_remote_sockaddr(
+            notify_fd,
+            notif,
+            notif.data.args[1],
+            notif.data.args[2] as usize,
+        ) {
+            Some(target) => target,
+            None => return NotifyDecision::Errno(libc::ESRCH),
+        };
+
+        if self.connect_allow.contains(&target) {
+            NotifyDecision::Continue
+        } else {
+            NotifyDecision::Errno(libc::ECONNREFUSED)
+        }
+    }
+}
+
+/// Re-checks `notif.id` via `SECCOMP_IOCTL_NOTIF_ID_VALID`: if the notifying thread has
+/// already been resumed or killed, its pid may have been recycled by something unrelated,
+/// so any read taken after that point can't be trusted. This is the exact TOCTOU the
+/// `seccomp_unotify(2)` manual page warns brokers to guard against.
+fn id_still_valid(notify_fd: RawFd, id: u64) -> bool {
+    unsafe { libc::ioctl(notify_fd, SECCOMP_IOCTL_NOTIF_ID_VALID, &id) == 0 }
+}
+
+fn open_remote_mem(notify_fd: RawFd, notif: &SeccompNotif) -> Option<std::fs::File> {
+    if !id_still_valid(notify_fd, notif.id) {
+        return None;
+    }
+    std::fs::File::open(format!("/proc/{}/mem", notif.pid)).ok()
+}
+
+/// Reads a NUL-terminated string out of the notifying thread's address space at
+/// `remote_addr`, bracketing the read with `id_still_valid` checks.
+fn read_remote_cstring(notify_fd: RawFd, notif: &SeccompNotif, remote_addr: u64) -> Option<String> {
+    let mem = open_remote_mem(notify_fd, notif)?;
+    let mut buf = [0u8; libc::PATH_MAX as usize];
+    let n = unsafe {
+        libc::pread(
+            mem.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            remote_addr as libc::off_t,
+        )
+    };
+    if n <= 0 || !id_still_valid(notify_fd, notif.id) {
+        return None;
+    }
+    let end = buf[..n as usize].iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&buf[..end]).ok().map(str::to_owned)
+}
+
+// `AF_VSOCK` from `<linux/vm_sockets.h>`. Not every `libc` version exposes this, so it's
+// spelled out the same way the seccomp-notify ioctl request codes above are.
+const AF_VSOCK: i32 = 40;
+
+/// Kernel ABI mirror of `struct sockaddr_vm` from `<linux/vm_sockets.h>`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockaddrVm {
+    svm_family: u16,
+    svm_reserved1: u16,
+    svm_port: u32,
+    svm_cid: u32,
+    svm_zero: [u8; 4],
+}
+
+/// Reads a `struct sockaddr` out of the notifying thread's address space at `remote_addr`
+/// and converts the `AF_INET`/`AF_INET6`/`AF_VSOCK` cases to a `ConnectTarget`, bracketing
+/// the read with `id_still_valid` checks.
+fn read_remote_sockaddr(
+    notify_fd: RawFd,
+    notif: &SeccompNotif,
+    remote_addr: u64,
+    len: usize,
+) -> Option<ConnectTarget> {
+    let mem = open_remote_mem(notify_fd, notif)?;
+    let len = len.min(std::mem::size_of::<libc::sockaddr_storage>());
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let n = unsafe {
+        libc::pread(
+            mem.as_raw_fd(),
+            &mut storage as *mut _ as *mut libc::c_void,
+            len,
+            remote_addr as libc::off_t,
+        )
+    };
+    if n < 0 || n as usize != len || !id_still_valid(notify_fd, notif.id) {
+        return None;
+    }
+
+    match storage.ss_family as i32 {
+        libc::AF_INET => {
+            let sin = unsafe { &*(&storage as *const _ as *const libc::sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(ConnectTarget::Ip(std::net::SocketAddr::from((ip, u16::from_be(sin.sin_port)))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(&storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(ConnectTarget::Ip(std::net::SocketAddr::from((ip, u16::from_be(sin6.sin6_port)))))
+        }
+        AF_VSOCK => {
+            let svm = unsafe { &*(&storage as *const _ as *const SockaddrVm) };
+            Some(ConnectTarget::Vsock { cid: svm.svm_cid, port: svm.svm_port })
+        }
+        _ => None,
+    }
+}
+
+/// Services `notify_fd` until the kernel closes it, which happens once every thread the
+/// filter applies to has exited -- i.e. this loop winds itself down on VM exit without
+/// needing an explicit shutdown signal.
+fn spawn_notify_broker(notify_fd: RawFd, policy: NotifyPolicy) {
+    thread::spawn(move || loop {
+        let mut notif: SeccompNotif = unsafe { std::mem::zeroed() };
+        // Safe: `notif` is sized and laid out exactly like the kernel's `struct
+        // seccomp_notif`; this ioctl blocks until the next notification arrives.
+        let ret = unsafe { libc::ioctl(notify_fd, SECCOMP_IOCTL_NOTIF_RECV, &mut notif) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.kind() {
+                ErrorKind::Interrupted => continue,
+                // The notification was already invalidated (its thread died some other
+                // way) -- not a reason to stop servicing the rest.
+                _ if err.raw_os_error() == Some(libc::ENOENT) => continue,
+                // Anything else means the fd itself is gone; nothing left to service.
+                _ => break,
+            }
+        }
+
+        let mut resp = SeccompNotifResp { id: notif.id, val: 0, error: 0, flags: 0 };
+        match policy.decide(notify_fd, &notif) {
+            NotifyDecision::Continue => resp.flags = SECCOMP_USER_NOTIF_FLAG_CONTINUE,
+            NotifyDecision::Errno(errno) => resp.error = errno,
+        }
+        // Safe for the same reason as the `NOTIF_RECV` call above; a failure here just
+        // means the target already went away, which is fine to ignore.
+        unsafe { libc::ioctl(notify_fd, SECCOMP_IOCTL_NOTIF_SEND, &mut resp) };
+    });
+}
+
+/// Like `add_seccomp_filter_with_mode`, but additionally routes `notify_syscalls` through a
+/// userspace broker enforcing `policy`, for syscalls whose arguments can't be fully bounded
+/// by BPF alone. The broker thread keeps running for as long as the filtered process does;
+/// it exits on its own once the kernel closes the notify fd, so there's nothing to tear
+/// down explicitly on VM exit.
+pub fn add_seccomp_filter_with_broker(
+    mode: SeccompMode,
+    notify_syscalls: &[SyscallNo],
+    policy: NotifyPolicy,
+) {
+    let default_action = match mode {
+        SeccompMode::Kill => Action::KillProcess,
+        SeccompMode::Log | SeccompMode::Trace => {
+            install_sigsys_handler();
+            Action::Trap
+        }
+    };
+    ACTIVE_MODE.store(mode.as_u8(), Ordering::Relaxed);
+
+    let excluded: Vec<usize> = notify_syscalls.iter().map(|&sysno| sysno as usize).collect();
+    let mut ctx = build_base_context(default_action, &excluded);
+    for &sysno in notify_syscalls {
+        ctx.add_rule(create_notify_rule(sysno as usize)).unwrap();
+    }
+
+    // Assumes `Context::load_with_notify` is the variant of `load` that also returns the
+    // notify fd for any `Action::Notify` rules added above (this crate fork doesn't
+    // otherwise expose `SECCOMP_RET_USER_NOTIF` support beyond the `Action` variant).
+    let notify_fd = ctx.load_with_notify().unwrap();
+    spawn_notify_broker(notify_fd, policy);
 }